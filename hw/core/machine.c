@@ -42,6 +42,9 @@
 GlobalProperty hw_compat_8_0[] = {
     { "migration", "multifd-flush-after-each-section", "on"},
     { TYPE_PCI_DEVICE, "x-pcie-ari-nextfn-1", "on" },
+    { "vmware-svga", "traces", "off" },
+    { "vmware-svga", "irq", "off" },
+    { "vmware-svga", "hide", "off" },
 };
 const size_t hw_compat_8_0_len = G_N_ELEMENTS(hw_compat_8_0);
 
@@ -47,9 +47,14 @@
 struct vmsvga_state_s {
     VGACommonState vga;
 
+    uint32_t flags;
     int invalidated;
     int enable;
+    int hidden;
     int config;
+    int traces;
+    uint32_t irq_mask;
+    uint32_t irq_status;
     struct {
         int id;
         int x;
@@ -108,6 +113,7 @@ struct pci_vmsvga_state_s {
 #define SVGA_INDEX_PORT         0x0
 #define SVGA_VALUE_PORT         0x1
 #define SVGA_BIOS_PORT          0x2
+#define SVGA_IRQSTATUS_PORT     0x8
 
 #define SVGA_VERSION_2
 
@@ -162,6 +168,9 @@ enum {
     SVGA_REG_MEM_REGS = 30,             /* Number of FIFO registers */
     SVGA_REG_NUM_DISPLAYS = 31,         /* Number of guest displays */
     SVGA_REG_PITCHLOCK = 32,            /* Fixed pitch for all modes */
+    SVGA_REG_IRQMASK = 33,              /* Interrupt mask */
+
+    SVGA_REG_TRACES = 45,               /* Trace-based updates in SVGA mode */
 
     SVGA_PALETTE_BASE = 1024,           /* Base of SVGA color map */
     SVGA_PALETTE_END  = SVGA_PALETTE_BASE + 767,
@@ -187,6 +196,19 @@ enum {
 #define SVGA_CAP_EXTENDED_FIFO          (1 << 15)
 #define SVGA_CAP_MULTIMON               (1 << 16)
 #define SVGA_CAP_PITCHLOCK              (1 << 17)
+#define SVGA_CAP_IRQMASK                (1 << 18)
+#define SVGA_CAP_TRACES                 (1 << 21)
+
+/* Optional features, bit numbers in vmsvga_state_s.flags */
+enum {
+    VMSVGA_FLAG_TRACES = 0,
+    VMSVGA_FLAG_IRQ = 1,
+    VMSVGA_FLAG_HIDE = 2,
+};
+
+/* Interrupt sources, in SVGA_REG_IRQMASK and the IRQSTATUS port */
+#define SVGA_IRQFLAG_ANY_FENCE          0x1
+#define SVGA_IRQFLAG_FIFO_PROGRESS      0x2
 
 /*
  * FIFO offsets (seen as an array of 32-bit words)
@@ -285,6 +307,13 @@ enum {
     SVGA_CMD_FENCE = 30,
 };
 
+/* Bits of the SVGA_REG_ENABLE register */
+enum {
+    SVGA_REG_ENABLE_DISABLE = 0,
+    SVGA_REG_ENABLE_ENABLE = (1 << 0),
+    SVGA_REG_ENABLE_HIDE = (1 << 1),
+};
+
 /* Legal values for the SVGA_REG_CURSOR_ON register in cursor bypass mode */
 enum {
     SVGA_CURSOR_ON_HIDE = 0,
@@ -293,7 +322,13 @@ enum {
     SVGA_CURSOR_ON_RESTORE_TO_FB = 3,
 };
 
-static inline bool vmsvga_verify_rect(DisplaySurface *surface,
+/*
+ * Is the rectangle inside a @width x @height screen, whose lines are
+ * @bypl bytes apart in VRAM?
+ */
+static inline bool vmsvga_verify_rect(struct vmsvga_state_s *s,
+                                      int width, int height,
+                                      int bypl, int bypp,
                                       const char *name,
                                       int x, int y, int w, int h)
 {
@@ -315,9 +350,8 @@ static inline bool vmsvga_verify_rect(DisplaySurface *surface,
                                                     w);
         return false;
     }
-    if (x + w > surface_width(surface)) {
-        trace_vmware_verify_rect_surface_bound_exceeded(name, "width",
-                                                        surface_width(surface),
+    if (x + w > width) {
+        trace_vmware_verify_rect_surface_bound_exceeded(name, "width", width,
                                                         "x", x, "w", w);
         return false;
     }
@@ -340,28 +374,72 @@ static inline bool vmsvga_verify_rect(DisplaySurface *surface,
                                                     y);
         return false;
     }
-    if (y + h > surface_height(surface)) {
-        trace_vmware_verify_rect_surface_bound_exceeded(name, "height",
-                                                        surface_height(surface),
+    if (y + h > height) {
+        trace_vmware_verify_rect_surface_bound_exceeded(name, "height", height,
                                                         "y", y, "h", h);
         return false;
     }
+    if (w && h &&
+        (uint64_t)(y + h - 1) * bypl + (uint64_t)(x + w) * bypp >
+        s->vga.vram_size) {
+        trace_vmware_verify_rect_vram_bound_exceeded(name, x, y, w, h,
+                                                     s->vga.vram_size);
+        return false;
+    }
 
     return true;
 }
 
+/* Does the programmed mode fit in the framebuffer?  */
+static bool vmsvga_mode_fits(struct vmsvga_state_s *s)
+{
+    uint64_t size = (uint64_t)s->new_width * s->new_height * s->new_depth / 8;
+
+    return s->new_width <= SVGA_MAX_WIDTH &&
+           s->new_height <= SVGA_MAX_HEIGHT &&
+           size <= s->vga.vram_size;
+}
+
+/*
+ * The 2D commands draw into VRAM laid out for the programmed mode, which
+ * is not necessarily what the console shows yet, or at all.  Refuse them
+ * until the guest has programmed a mode that fits in VRAM.
+ */
+static inline bool vmsvga_verify_mode_rect(struct vmsvga_state_s *s,
+                                           const char *name,
+                                           int x, int y, int w, int h)
+{
+    if (!s->new_width || !s->new_height || !vmsvga_mode_fits(s)) {
+        trace_vmware_verify_rect_no_mode(name);
+        return false;
+    }
+    return vmsvga_verify_rect(s, s->new_width, s->new_height,
+                              s->new_width * s->new_depth / 8,
+                              s->new_depth / 8, name, x, y, w, h);
+}
+
+/* Redraws go through the console surface, which must be VRAM itself */
+static inline bool vmsvga_verify_surface_rect(struct vmsvga_state_s *s,
+                                              DisplaySurface *surface,
+                                              const char *name,
+                                              int x, int y, int w, int h)
+{
+    if (surface_data(surface) != s->vga.vram_ptr) {
+        trace_vmware_verify_rect_not_vram(name);
+        return false;
+    }
+    return vmsvga_verify_rect(s, surface_width(surface),
+                              surface_height(surface), surface_stride(surface),
+                              surface_bytes_per_pixel(surface),
+                              name, x, y, w, h);
+}
+
 static inline void vmsvga_update_rect(struct vmsvga_state_s *s,
                                       int x, int y, int w, int h)
 {
     DisplaySurface *surface = qemu_console_surface(s->vga.con);
-    int line;
-    int bypl;
-    int width;
-    int start;
-    uint8_t *src;
-    uint8_t *dst;
 
-    if (!vmsvga_verify_rect(surface, __func__, x, y, w, h)) {
+    if (!vmsvga_verify_surface_rect(s, surface, __func__, x, y, w, h)) {
         /* go for a fullscreen update as fallback */
         x = 0;
         y = 0;
@@ -369,28 +447,25 @@ static inline void vmsvga_update_rect(struct vmsvga_state_s *s,
         h = surface_height(surface);
     }
 
-    bypl = surface_stride(surface);
-    width = surface_bytes_per_pixel(surface) * w;
-    start = surface_bytes_per_pixel(surface) * x + bypl * y;
-    src = s->vga.vram_ptr + start;
-    dst = surface_data(surface) + start;
-
-    for (line = h; line > 0; line--, src += bypl, dst += bypl) {
-        memcpy(dst, src, width);
-    }
+    /* the surface is VRAM itself, see vmsvga_update_rect_flush() */
+    trace_vmware_update_rect(x, y, w, h);
     dpy_gfx_update(s->vga.con, x, y, w, h);
 }
 
 static inline void vmsvga_update_rect_flush(struct vmsvga_state_s *s)
 {
+    DisplaySurface *surface = qemu_console_surface(s->vga.con);
     struct vmsvga_rect_s *rect;
 
-    if (s->invalidated) {
+    /*
+     * Only redraw from VRAM when the console shows it.  Until the guest
+     * programs a valid mode, it may still show a VGA mode surface.
+     */
+    if (s->invalidated || s->hidden ||
+        surface_data(surface) != s->vga.vram_ptr) {
         s->redraw_fifo_last = 0;
         return;
     }
-    /* Overlapping region updates can be optimised out here - if someone
-     * knows a smart algorithm to do that, please share.  */
     for (int i = 0; i < s->redraw_fifo_last; i++) {
         rect = &s->redraw_fifo[i];
         vmsvga_update_rect(s, rect->x, rect->y, rect->w, rect->h);
@@ -399,39 +474,101 @@ static inline void vmsvga_update_rect_flush(struct vmsvga_state_s *s)
     s->redraw_fifo_last = 0;
 }
 
+static inline bool vmsvga_rect_contains(const struct vmsvga_rect_s *a,
+                                        const struct vmsvga_rect_s *b)
+{
+    return a->x <= b->x && a->x + a->w >= b->x + b->w &&
+           a->y <= b->y && a->y + a->h >= b->y + b->h;
+}
+
+/*
+ * Two rects in the same columns that touch or overlap vertically, or in
+ * the same rows that touch or overlap horizontally, cover exactly their
+ * bounding box.
+ */
+static inline bool vmsvga_rect_merge(struct vmsvga_rect_s *a,
+                                     const struct vmsvga_rect_s *b)
+{
+    int end;
+
+    if (a->x == b->x && a->w == b->w &&
+        a->y <= b->y + b->h && b->y <= a->y + a->h) {
+        end = MAX(a->y + a->h, b->y + b->h);
+        a->y = MIN(a->y, b->y);
+        a->h = end - a->y;
+        return true;
+    }
+    if (a->y == b->y && a->h == b->h &&
+        a->x <= b->x + b->w && b->x <= a->x + a->w) {
+        end = MAX(a->x + a->w, b->x + b->w);
+        a->x = MIN(a->x, b->x);
+        a->w = end - a->x;
+        return true;
+    }
+    return false;
+}
+
 static inline void vmsvga_update_rect_delayed(struct vmsvga_state_s *s,
                 int x, int y, int w, int h)
 {
+    DisplaySurface *surface = qemu_console_surface(s->vga.con);
+    struct vmsvga_rect_s new = { .x = x, .y = y, .w = w, .h = h };
+    int i, last;
+
+    if (surface_data(surface) != s->vga.vram_ptr) {
+        /* nothing to redraw, see vmsvga_update_rect_flush() */
+        return;
+    }
+    if (!vmsvga_verify_surface_rect(s, surface, __func__, x, y, w, h)) {
+        /* go for a fullscreen update as fallback */
+        new.x = 0;
+        new.y = 0;
+        new.w = surface_width(surface);
+        new.h = surface_height(surface);
+    }
+
+    /*
+     * Guests tend to send many small, overlapping updates per frame.
+     * Since all of them are copied from VRAM at flush time, the order does
+     * not matter, and queued rects that the new one covers or can be
+     * merged with are folded into it.
+     */
+    for (i = 0; i < s->redraw_fifo_last; i++) {
+        if (vmsvga_rect_contains(&s->redraw_fifo[i], &new)) {
+            return;
+        }
+    }
+    for (i = last = 0; i < s->redraw_fifo_last; i++) {
+        if (!vmsvga_rect_contains(&new, &s->redraw_fifo[i]) &&
+            !vmsvga_rect_merge(&new, &s->redraw_fifo[i])) {
+            s->redraw_fifo[last++] = s->redraw_fifo[i];
+        }
+    }
+    s->redraw_fifo_last = last;
 
     if (s->redraw_fifo_last >= REDRAW_FIFO_LEN) {
         trace_vmware_update_rect_delayed_flush();
         vmsvga_update_rect_flush(s);
     }
 
-    struct vmsvga_rect_s *rect = &s->redraw_fifo[s->redraw_fifo_last++];
-
-    rect->x = x;
-    rect->y = y;
-    rect->w = w;
-    rect->h = h;
+    s->redraw_fifo[s->redraw_fifo_last++] = new;
 }
 
 #ifdef HW_RECT_ACCEL
 static inline int vmsvga_copy_rect(struct vmsvga_state_s *s,
                 int x0, int y0, int x1, int y1, int w, int h)
 {
-    DisplaySurface *surface = qemu_console_surface(s->vga.con);
     uint8_t *vram = s->vga.vram_ptr;
-    int bypl = surface_stride(surface);
-    int bypp = surface_bytes_per_pixel(surface);
+    int bypp = s->new_depth / 8;
+    int bypl = bypp * s->new_width;
     int width = bypp * w;
     int line = h;
     uint8_t *ptr[2];
 
-    if (!vmsvga_verify_rect(surface, "vmsvga_copy_rect/src", x0, y0, w, h)) {
+    if (!vmsvga_verify_mode_rect(s, "vmsvga_copy_rect/src", x0, y0, w, h)) {
         return -1;
     }
-    if (!vmsvga_verify_rect(surface, "vmsvga_copy_rect/dst", x1, y1, w, h)) {
+    if (!vmsvga_verify_mode_rect(s, "vmsvga_copy_rect/dst", x1, y1, w, h)) {
         return -1;
     }
 
@@ -458,9 +595,9 @@ static inline int vmsvga_copy_rect(struct vmsvga_state_s *s,
 static inline int vmsvga_fill_rect(struct vmsvga_state_s *s,
                 uint32_t c, int x, int y, int w, int h)
 {
-    DisplaySurface *surface = qemu_console_surface(s->vga.con);
-    int bypl = surface_stride(surface);
-    int width = surface_bytes_per_pixel(surface) * w;
+    int bypp = s->new_depth / 8;
+    int bypl = bypp * s->new_width;
+    int width = bypp * w;
     int line = h;
     int column;
     uint8_t *fst;
@@ -468,7 +605,7 @@ static inline int vmsvga_fill_rect(struct vmsvga_state_s *s,
     uint8_t *src;
     uint8_t col[4];
 
-    if (!vmsvga_verify_rect(surface, __func__, x, y, w, h)) {
+    if (!vmsvga_verify_mode_rect(s, __func__, x, y, w, h)) {
         return -1;
     }
 
@@ -477,14 +614,14 @@ static inline int vmsvga_fill_rect(struct vmsvga_state_s *s,
     col[2] = c >> 16;
     col[3] = c >> 24;
 
-    fst = s->vga.vram_ptr + surface_bytes_per_pixel(surface) * x + bypl * y;
+    fst = s->vga.vram_ptr + bypp * x + bypl * y;
 
     if (line--) {
         dst = fst;
         src = col;
         for (column = width; column > 0; column--) {
             *(dst++) = *(src++);
-            if (src - col == surface_bytes_per_pixel(surface)) {
+            if (src - col == bypp) {
                 src = col;
             }
         }
@@ -548,8 +685,9 @@ static inline void vmsvga_cursor_define(struct vmsvga_state_s *s,
 #endif
         break;
     default:
-        fprintf(stderr, "%s: unhandled bpp %d, using fallback cursor\n",
-                __func__, c->bpp);
+        qemu_log_mask(LOG_UNIMP,
+                      "%s: unhandled bpp %d, using fallback cursor\n",
+                      __func__, c->bpp);
         cursor_unref(qc);
         qc = cursor_builtin_left_ptr();
     }
@@ -559,6 +697,21 @@ static inline void vmsvga_cursor_define(struct vmsvga_state_s *s,
 }
 #endif
 
+/*
+ * The first words of the FIFO memory are registers (SVGA_FIFO_*) rather
+ * than command data, always access them through these.
+ */
+static inline uint32_t vmsvga_fifo_reg_read(struct vmsvga_state_s *s, int reg)
+{
+    return le32_to_cpu(s->fifo[reg]);
+}
+
+static inline void vmsvga_fifo_reg_write(struct vmsvga_state_s *s, int reg,
+                                         uint32_t value)
+{
+    s->fifo[reg] = cpu_to_le32(value);
+}
+
 static inline int vmsvga_fifo_length(struct vmsvga_state_s *s)
 {
     int num;
@@ -567,10 +720,10 @@ static inline int vmsvga_fifo_length(struct vmsvga_state_s *s)
         return 0;
     }
 
-    s->fifo_min  = le32_to_cpu(s->fifo[SVGA_FIFO_MIN]);
-    s->fifo_max  = le32_to_cpu(s->fifo[SVGA_FIFO_MAX]);
-    s->fifo_next = le32_to_cpu(s->fifo[SVGA_FIFO_NEXT]);
-    s->fifo_stop = le32_to_cpu(s->fifo[SVGA_FIFO_STOP]);
+    s->fifo_min  = vmsvga_fifo_reg_read(s, SVGA_FIFO_MIN);
+    s->fifo_max  = vmsvga_fifo_reg_read(s, SVGA_FIFO_MAX);
+    s->fifo_next = vmsvga_fifo_reg_read(s, SVGA_FIFO_NEXT);
+    s->fifo_stop = vmsvga_fifo_reg_read(s, SVGA_FIFO_STOP);
 
     /* Check range and alignment.  */
     if ((s->fifo_min | s->fifo_max | s->fifo_next | s->fifo_stop) & 3) {
@@ -588,6 +741,10 @@ static inline int vmsvga_fifo_length(struct vmsvga_state_s *s)
     if (s->fifo_max < s->fifo_min + 10 * KiB) {
         return 0;
     }
+    if (s->fifo_stop < s->fifo_min || s->fifo_stop >= s->fifo_max ||
+        s->fifo_next < s->fifo_min || s->fifo_next >= s->fifo_max) {
+        return 0;
+    }
 
     num = s->fifo_next - s->fifo_stop;
     if (num < 0) {
@@ -604,7 +761,6 @@ static inline uint32_t vmsvga_fifo_read_raw(struct vmsvga_state_s *s)
     if (s->fifo_stop >= s->fifo_max) {
         s->fifo_stop = s->fifo_min;
     }
-    s->fifo[SVGA_FIFO_STOP] = cpu_to_le32(s->fifo_stop);
     return cmd;
 }
 
@@ -613,20 +769,84 @@ static inline uint32_t vmsvga_fifo_read(struct vmsvga_state_s *s)
     return le32_to_cpu(vmsvga_fifo_read_raw(s));
 }
 
+/*
+ * Can the command starting at @cmd_start, with @args words left to read,
+ * ever be complete in the FIFO?  One word always stays free so that a
+ * full FIFO does not look empty.
+ */
+static inline bool vmsvga_fifo_cmd_fits(struct vmsvga_state_s *s,
+                                        uint32_t cmd_start, int args)
+{
+    uint32_t size = s->fifo_max - s->fifo_min;
+    uint32_t used = (s->fifo_stop + size - cmd_start) % size;
+
+    return args >= 0 && (uint64_t)(used >> 2) + args < size >> 2;
+}
+
+/*
+ * VGA mode finds guest framebuffer writes through the dirty log.  In SVGA
+ * mode the guest reports them with UPDATE commands instead, unless it has
+ * asked for trace-based updates.
+ */
+static void vmsvga_update_dirty_log(struct vmsvga_state_s *s)
+{
+    if (s->enable && s->config && !s->traces) {
+        vga_dirty_log_stop(&s->vga);
+    } else {
+        vga_dirty_log_start(&s->vga);
+    }
+}
+
+/*
+ * Leaving SVGA mode: throw away any commands the guest queued but we did
+ * not get to, together with the damage they produced, so that the next
+ * ENABLE starts from a clean FIFO instead of replaying stale drawing.
+ * CONFIG_DONE is kept so that the guest does not have to set up the FIFO
+ * memory again.
+ */
+static void vmsvga_disable(struct vmsvga_state_s *s)
+{
+    if (vmsvga_fifo_length(s) > 0) {
+        s->fifo_stop = s->fifo_next;
+        vmsvga_fifo_reg_write(s, SVGA_FIFO_STOP, s->fifo_stop);
+    }
+    s->redraw_fifo_last = 0;
+    s->syncing = 0;
+}
+
+static void vmsvga_update_irq(struct vmsvga_state_s *s)
+{
+    struct pci_vmsvga_state_s *pci_vmsvga
+        = container_of(s, struct pci_vmsvga_state_s, chip);
+
+    if (s->flags & (1 << VMSVGA_FLAG_IRQ)) {
+        pci_set_irq(PCI_DEVICE(pci_vmsvga), !!(s->irq_status & s->irq_mask));
+    }
+}
+
 static void vmsvga_fifo_run(struct vmsvga_state_s *s)
 {
     uint32_t cmd, colour;
     int args, len, maxloop = 1024;
     int x, y, dx, dy, width, height;
     struct vmsvga_cursor_definition_s cursor;
-    uint32_t cmd_start;
+    uint32_t cmd_start, first_stop;
+    bool active;
 
     len = vmsvga_fifo_length(s);
+    active = len > 0;
+    /* vmsvga_fifo_length() has just reloaded STOP from the guest */
+    first_stop = s->fifo_stop;
+    if (active) {
+        trace_vmware_fifo_run_begin(s->fifo_stop, s->fifo_next, len);
+    }
     while (len > 0 && --maxloop > 0) {
         /* May need to go back to the start of the command if incomplete */
         cmd_start = s->fifo_stop;
 
-        switch (cmd = vmsvga_fifo_read(s)) {
+        cmd = vmsvga_fifo_read(s);
+        trace_vmware_fifo_command(cmd, cmd_start);
+        switch (cmd) {
         case SVGA_CMD_UPDATE:
         case SVGA_CMD_UPDATE_VERBOSE:
             len -= 5;
@@ -700,7 +920,8 @@ static void vmsvga_fifo_run(struct vmsvga_state_s *s)
                 || cursor.bpp > 32
                 || SVGA_BITMAP_SIZE(x, y) > ARRAY_SIZE(cursor.mask)
                 || SVGA_PIXMAP_SIZE(x, y, cursor.bpp)
-                    > ARRAY_SIZE(cursor.image)) {
+                    > ARRAY_SIZE(cursor.image)
+                || !vmsvga_fifo_cmd_fits(s, cmd_start, args)) {
                     goto badcmd;
             }
 
@@ -772,6 +993,18 @@ static void vmsvga_fifo_run(struct vmsvga_state_s *s)
         default:
             args = 0;
         badcmd:
+            if (!vmsvga_fifo_cmd_fits(s, cmd_start, args)) {
+                /*
+                 * The command can never fit in the FIFO, so there is no
+                 * way to find the next one.  Drop everything queued.
+                 */
+                qemu_log_mask(LOG_GUEST_ERROR,
+                              "%s: Command 0x%02x with bogus length %d, "
+                              "discarding FIFO\n", __func__, cmd, args);
+                s->fifo_stop = s->fifo_next;
+                len = 0;
+                break;
+            }
             len -= args;
             if (len < 0) {
                 goto rewind;
@@ -779,24 +1012,50 @@ static void vmsvga_fifo_run(struct vmsvga_state_s *s)
             while (args--) {
                 vmsvga_fifo_read(s);
             }
-            printf("%s: Unknown command 0x%02x in SVGA command FIFO\n",
-                   __func__, cmd);
+            qemu_log_mask(LOG_UNIMP,
+                          "%s: Unknown command 0x%02x in SVGA command FIFO\n",
+                          __func__, cmd);
             break;
 
         rewind:
             s->fifo_stop = cmd_start;
-            s->fifo[SVGA_FIFO_STOP] = cpu_to_le32(s->fifo_stop);
             break;
         }
     }
 
-    s->syncing = 0;
+    /*
+     * Only hand the consumed space back to the guest once per pass rather
+     * than after every word, the guest driver polls this location.
+     */
+    if (active) {
+        vmsvga_fifo_reg_write(s, SVGA_FIFO_STOP, s->fifo_stop);
+        trace_vmware_fifo_run_end(s->fifo_stop);
+    }
+
+    /* Let a guest waiting for FIFO space know that some was freed */
+    if (active && s->fifo_stop != first_stop &&
+        (s->irq_mask & SVGA_IRQFLAG_FIFO_PROGRESS)) {
+        s->irq_status |= SVGA_IRQFLAG_FIFO_PROGRESS;
+        vmsvga_update_irq(s);
+    }
+
+    /*
+     * BUSY must stay set until everything queued before the SYNC has been
+     * consumed, which may take more than one pass when maxloop runs out.
+     * A command is only rewound while it can still be completed, those
+     * too long for the FIFO are discarded above, so BUSY stays set only
+     * as long as the guest leaves a command half written.
+     */
+    if (vmsvga_fifo_length(s) == 0) {
+        s->syncing = 0;
+    }
 }
 
 static uint32_t vmsvga_index_read(void *opaque, uint32_t address)
 {
     struct vmsvga_state_s *s = opaque;
 
+    trace_vmware_index_read(s->index);
     return s->index;
 }
 
@@ -804,6 +1063,7 @@ static void vmsvga_index_write(void *opaque, uint32_t address, uint32_t index)
 {
     struct vmsvga_state_s *s = opaque;
 
+    trace_vmware_index_write(index);
     s->index = index;
 }
 
@@ -821,7 +1081,10 @@ static uint32_t vmsvga_value_read(void *opaque, uint32_t address)
         break;
 
     case SVGA_REG_ENABLE:
-        ret = s->enable;
+        ret = s->enable ? SVGA_REG_ENABLE_ENABLE : SVGA_REG_ENABLE_DISABLE;
+        if (s->hidden) {
+            ret |= SVGA_REG_ENABLE_HIDE;
+        }
         break;
 
     case SVGA_REG_WIDTH:
@@ -909,6 +1172,12 @@ static uint32_t vmsvga_value_read(void *opaque, uint32_t address)
                     SVGA_CAP_CURSOR_BYPASS;
         }
 #endif
+        if (s->flags & (1 << VMSVGA_FLAG_IRQ)) {
+            caps |= SVGA_CAP_IRQMASK;
+        }
+        if (s->flags & (1 << VMSVGA_FLAG_TRACES)) {
+            caps |= SVGA_CAP_TRACES;
+        }
         ret = caps;
         break;
 
@@ -929,6 +1198,10 @@ static uint32_t vmsvga_value_read(void *opaque, uint32_t address)
 
     case SVGA_REG_SYNC:
     case SVGA_REG_BUSY:
+        if (s->syncing) {
+            /* The guest is waiting for us, keep draining the FIFO */
+            vmsvga_fifo_run(s);
+        }
         ret = s->syncing;
         break;
 
@@ -963,6 +1236,14 @@ static uint32_t vmsvga_value_read(void *opaque, uint32_t address)
         ret = 0;
         break;
 
+    case SVGA_REG_IRQMASK:
+        ret = s->irq_mask;
+        break;
+
+    case SVGA_REG_TRACES:
+        ret = s->traces;
+        break;
+
     default:
         if (s->index >= SVGA_SCRATCH_BASE &&
             s->index < SVGA_SCRATCH_BASE + s->scratch_size) {
@@ -1004,18 +1285,29 @@ static void vmsvga_value_write(void *opaque, uint32_t address, uint32_t value)
         break;
 
     case SVGA_REG_ENABLE:
-        s->enable = !!value;
+        if (!(s->flags & (1 << VMSVGA_FLAG_HIDE)) && value) {
+            /* older machine types take any non-zero value as enable */
+            value = SVGA_REG_ENABLE_ENABLE;
+        }
+        if (s->enable && !(value & SVGA_REG_ENABLE_ENABLE)) {
+            vmsvga_disable(s);
+        }
+        if ((value & SVGA_REG_ENABLE_ENABLE) && !vmsvga_mode_fits(s)) {
+            qemu_log_mask(LOG_GUEST_ERROR,
+                          "%s: Mode %ix%i @ %i bpp exceeds %u bytes of VRAM\n",
+                          __func__, s->new_width, s->new_height, s->new_depth,
+                          s->vga.vram_size);
+            break;
+        }
+        s->enable = !!(value & SVGA_REG_ENABLE_ENABLE);
+        s->hidden = !!(value & SVGA_REG_ENABLE_HIDE);
         s->invalidated = 1;
         s->vga.hw_ops->invalidate(&s->vga);
-        if (s->enable && s->config) {
-            vga_dirty_log_stop(&s->vga);
-        } else {
-            vga_dirty_log_start(&s->vga);
-        }
+        vmsvga_update_dirty_log(s);
         break;
 
     case SVGA_REG_WIDTH:
-        if (value <= SVGA_MAX_WIDTH) {
+        if (value && value <= SVGA_MAX_WIDTH) {
             s->new_width = value;
             s->invalidated = 1;
         } else {
@@ -1025,7 +1317,7 @@ static void vmsvga_value_write(void *opaque, uint32_t address, uint32_t value)
         break;
 
     case SVGA_REG_HEIGHT:
-        if (value <= SVGA_MAX_HEIGHT) {
+        if (value && value <= SVGA_MAX_HEIGHT) {
             s->new_height = value;
             s->invalidated = 1;
         } else {
@@ -1046,9 +1338,9 @@ static void vmsvga_value_write(void *opaque, uint32_t address, uint32_t value)
     case SVGA_REG_CONFIG_DONE:
         if (value) {
             s->fifo = (uint32_t *) s->fifo_ptr;
-            vga_dirty_log_stop(&s->vga);
         }
         s->config = !!value;
+        vmsvga_update_dirty_log(s);
         break;
 
     case SVGA_REG_SYNC:
@@ -1089,6 +1381,27 @@ static void vmsvga_value_write(void *opaque, uint32_t address, uint32_t value)
 #endif
         break;
 
+    case SVGA_REG_IRQMASK:
+        if (!(s->flags & (1 << VMSVGA_FLAG_IRQ))) {
+            qemu_log_mask(LOG_GUEST_ERROR,
+                          "%s: Interrupts are not supported\n", __func__);
+            break;
+        }
+        s->irq_mask = value;
+        vmsvga_update_irq(s);
+        break;
+
+    case SVGA_REG_TRACES:
+        if (!(s->flags & (1 << VMSVGA_FLAG_TRACES))) {
+            qemu_log_mask(LOG_GUEST_ERROR,
+                          "%s: Traces are not supported\n", __func__);
+            break;
+        }
+        s->traces = !!value;
+        s->invalidated = 1;
+        vmsvga_update_dirty_log(s);
+        break;
+
     case SVGA_REG_DEPTH:
     case SVGA_REG_MEM_REGS:
     case SVGA_REG_NUM_DISPLAYS:
@@ -1107,24 +1420,60 @@ static void vmsvga_value_write(void *opaque, uint32_t address, uint32_t value)
     }
 }
 
+static uint32_t vmsvga_irqstatus_read(void *opaque, uint32_t address)
+{
+    struct vmsvga_state_s *s = opaque;
+
+    trace_vmware_irqstatus_read(s->irq_status);
+    return s->irq_status;
+}
+
+static void vmsvga_irqstatus_write(void *opaque, uint32_t address,
+                                   uint32_t data)
+{
+    struct vmsvga_state_s *s = opaque;
+
+    /* Writing a bit acknowledges that interrupt source */
+    trace_vmware_irqstatus_write(data);
+    s->irq_status &= ~data;
+    vmsvga_update_irq(s);
+}
+
 static uint32_t vmsvga_bios_read(void *opaque, uint32_t address)
 {
-    printf("%s: what are we supposed to return?\n", __func__);
+    qemu_log_mask(LOG_UNIMP, "%s: what are we supposed to return?\n",
+                  __func__);
+    trace_vmware_bios_read(0xcafe);
     return 0xcafe;
 }
 
 static void vmsvga_bios_write(void *opaque, uint32_t address, uint32_t data)
 {
-    printf("%s: what are we supposed to do with (%08x)?\n", __func__, data);
+    qemu_log_mask(LOG_UNIMP, "%s: what are we supposed to do with (%08x)?\n",
+                  __func__, data);
+    trace_vmware_bios_write(data);
 }
 
 static inline void vmsvga_check_size(struct vmsvga_state_s *s)
 {
     DisplaySurface *surface = qemu_console_surface(s->vga.con);
 
+    if (!s->new_width || !s->new_height) {
+        /* no mode programmed yet, nothing to switch to */
+        return;
+    }
+
+    if (!vmsvga_mode_fits(s)) {
+        /* keep scanning out the previous mode */
+        trace_vmware_setmode_invalid(s->new_width, s->new_height, s->new_depth,
+                                     s->vga.vram_size);
+        return;
+    }
+
     if (s->new_width != surface_width(surface) ||
         s->new_height != surface_height(surface) ||
-        s->new_depth != surface_bits_per_pixel(surface)) {
+        s->new_depth != surface_bits_per_pixel(surface) ||
+        surface_data(surface) != s->vga.vram_ptr) {
         int stride = (s->new_depth * s->new_width) / 8;
         pixman_format_code_t format =
             qemu_default_pixman_format(s->new_depth, true);
@@ -1137,6 +1486,69 @@ static inline void vmsvga_check_size(struct vmsvga_state_s *s)
     }
 }
 
+/* Turn framebuffer lines written by the guest into redraw rectangles */
+static void vmsvga_update_traced(struct vmsvga_state_s *s)
+{
+    DisplaySurface *surface = qemu_console_surface(s->vga.con);
+    int width = surface_width(surface);
+    int height = surface_height(surface);
+    int stride = surface_stride(surface);
+    DirtyBitmapSnapshot *snap;
+    int y, y_start = -1;
+
+    /*
+     * Only a surface showing VRAM has anything to redraw, e.g. not the
+     * blank one while hidden or one left over from VGA mode.
+     */
+    if (surface_data(surface) != s->vga.vram_ptr) {
+        return;
+    }
+    /* the snapshot must not extend past the end of VRAM */
+    height = MIN(height, s->vga.vram_size / stride);
+
+    snap = memory_region_snapshot_and_clear_dirty(&s->vga.vram, 0,
+                                                  stride * height,
+                                                  DIRTY_MEMORY_VGA);
+    for (y = 0; y < height; y++) {
+        if (memory_region_snapshot_get_dirty(&s->vga.vram, snap,
+                                             y * stride, stride)) {
+            if (y_start < 0) {
+                y_start = y;
+            }
+        } else if (y_start >= 0) {
+            vmsvga_update_rect_delayed(s, 0, y_start, width, y - y_start);
+            y_start = -1;
+        }
+    }
+    if (y_start >= 0) {
+        vmsvga_update_rect_delayed(s, 0, y_start, width, height - y_start);
+    }
+    g_free(snap);
+}
+
+/*
+ * While the guest hides the output, show a blank surface instead of VRAM,
+ * following mode changes made in the meantime.  vmsvga_check_size()
+ * switches back to VRAM.
+ */
+static void vmsvga_blank_display(struct vmsvga_state_s *s)
+{
+    DisplaySurface *surface = qemu_console_surface(s->vga.con);
+    int width = surface_width(surface);
+    int height = surface_height(surface);
+
+    if (s->new_width && s->new_height && vmsvga_mode_fits(s)) {
+        width = s->new_width;
+        height = s->new_height;
+    }
+    if (s->invalidated || surface_data(surface) == s->vga.vram_ptr ||
+        width != surface_width(surface) || height != surface_height(surface)) {
+        surface = qemu_create_displaysurface(width, height);
+        dpy_gfx_replace_surface(s->vga.con, surface);
+        s->invalidated = 1;
+    }
+}
+
 static void vmsvga_update_display(void *opaque)
 {
     struct vmsvga_state_s *s = opaque;
@@ -1147,9 +1559,16 @@ static void vmsvga_update_display(void *opaque)
         return;
     }
 
-    vmsvga_check_size(s);
+    if (s->hidden) {
+        vmsvga_blank_display(s);
+    } else {
+        vmsvga_check_size(s);
+    }
 
     vmsvga_fifo_run(s);
+    if (s->traces) {
+        vmsvga_update_traced(s);
+    }
     vmsvga_update_rect_flush(s);
 
     if (s->invalidated) {
@@ -1165,11 +1584,16 @@ static void vmsvga_reset(DeviceState *dev)
 
     s->index = 0;
     s->enable = 0;
+    s->hidden = 0;
     s->config = 0;
     s->svgaid = SVGA_ID;
     s->cursor.on = 0;
     s->redraw_fifo_last = 0;
     s->syncing = 0;
+    s->traces = 0;
+    s->irq_mask = 0;
+    s->irq_status = 0;
+    vmsvga_update_irq(s);
 
     vga_dirty_log_start(&s->vga);
 }
@@ -1202,9 +1626,65 @@ static int vmsvga_post_load(void *opaque, int version_id)
     if (s->config) {
         s->fifo = (uint32_t *) s->fifo_ptr;
     }
+    vmsvga_update_dirty_log(s);
     return 0;
 }
 
+static bool vmsvga_traces_needed(void *opaque)
+{
+    struct vmsvga_state_s *s = opaque;
+
+    return s->traces;
+}
+
+static bool vmsvga_hidden_needed(void *opaque)
+{
+    struct vmsvga_state_s *s = opaque;
+
+    return s->hidden;
+}
+
+static const VMStateDescription vmstate_vmware_vga_hidden = {
+    .name = "vmware_vga_internal/hidden",
+    .version_id = 1,
+    .minimum_version_id = 1,
+    .needed = vmsvga_hidden_needed,
+    .fields = (VMStateField[]) {
+        VMSTATE_INT32(hidden, struct vmsvga_state_s),
+        VMSTATE_END_OF_LIST()
+    }
+};
+
+static const VMStateDescription vmstate_vmware_vga_traces = {
+    .name = "vmware_vga_internal/traces",
+    .version_id = 1,
+    .minimum_version_id = 1,
+    .needed = vmsvga_traces_needed,
+    .fields = (VMStateField[]) {
+        VMSTATE_INT32(traces, struct vmsvga_state_s),
+        VMSTATE_END_OF_LIST()
+    }
+};
+
+static bool vmsvga_irq_needed(void *opaque)
+{
+    struct vmsvga_state_s *s = opaque;
+
+    return s->irq_mask || s->irq_status;
+}
+
+static const VMStateDescription vmstate_vmware_vga_irq = {
+    .name = "vmware_vga_internal/irq",
+    .version_id = 1,
+    .minimum_version_id = 1,
+    .needed = vmsvga_irq_needed,
+    .fields = (VMStateField[]) {
+        VMSTATE_UINT32(irq_mask, struct vmsvga_state_s),
+        VMSTATE_UINT32(irq_status, struct vmsvga_state_s),
+        VMSTATE_END_OF_LIST()
+    }
+};
+
 static const VMStateDescription vmstate_vmware_vga_internal = {
     .name = "vmware_vga_internal",
     .version_id = 0,
@@ -1228,6 +1708,12 @@ static const VMStateDescription vmstate_vmware_vga_internal = {
         VMSTATE_INT32(syncing, struct vmsvga_state_s),
         VMSTATE_UNUSED(4), /* was fb_size */
         VMSTATE_END_OF_LIST()
+    },
+    .subsections = (const VMStateDescription*[]) {
+        &vmstate_vmware_vga_traces,
+        &vmstate_vmware_vga_hidden,
+        &vmstate_vmware_vga_irq,
+        NULL
     }
 };
 
@@ -1276,6 +1762,11 @@ static uint64_t vmsvga_io_read(void *opaque, hwaddr addr, unsigned size)
     case SVGA_IO_MUL * SVGA_INDEX_PORT: return vmsvga_index_read(s, addr);
     case SVGA_IO_MUL * SVGA_VALUE_PORT: return vmsvga_value_read(s, addr);
     case SVGA_IO_MUL * SVGA_BIOS_PORT: return vmsvga_bios_read(s, addr);
+    case SVGA_IO_MUL * SVGA_IRQSTATUS_PORT:
+        if (s->flags & (1 << VMSVGA_FLAG_IRQ)) {
+            return vmsvga_irqstatus_read(s, addr);
+        }
+        return -1u;
     default: return -1u;
     }
 }
@@ -1295,6 +1786,11 @@ static void vmsvga_io_write(void *opaque, hwaddr addr,
     case SVGA_IO_MUL * SVGA_BIOS_PORT:
         vmsvga_bios_write(s, addr, data);
         break;
+    case SVGA_IO_MUL * SVGA_IRQSTATUS_PORT:
+        if (s->flags & (1 << VMSVGA_FLAG_IRQ)) {
+            vmsvga_irqstatus_write(s, addr, data);
+        }
+        break;
     }
 }
 
@@ -1319,6 +1815,9 @@ static void pci_vmsvga_realize(PCIDevice *dev, Error **errp)
     dev->config[PCI_CACHE_LINE_SIZE] = 0x08;
     dev->config[PCI_LATENCY_TIMER] = 0x40;
     dev->config[PCI_INTERRUPT_LINE] = 0xff;          /* End */
+    if (s->chip.flags & (1 << VMSVGA_FLAG_IRQ)) {
+        dev->config[PCI_INTERRUPT_PIN] = 1;
+    }
 
     memory_region_init_io(&s->io_bar, OBJECT(dev), &vmsvga_io_ops, &s->chip,
                           "vmsvga-io", 0x10);
@@ -1339,6 +1838,12 @@ static Property vga_vmware_properties[] = {
                        chip.vga.vram_size_mb, 16),
     DEFINE_PROP_BOOL("global-vmstate", struct pci_vmsvga_state_s,
                      chip.vga.global_vmstate, false),
+    DEFINE_PROP_BIT("traces", struct pci_vmsvga_state_s, chip.flags,
+                    VMSVGA_FLAG_TRACES, true),
+    DEFINE_PROP_BIT("irq", struct pci_vmsvga_state_s, chip.flags,
+                    VMSVGA_FLAG_IRQ, true),
+    DEFINE_PROP_BIT("hide", struct pci_vmsvga_state_s, chip.flags,
+                    VMSVGA_FLAG_HIDE, true),
     DEFINE_PROP_END_OF_LIST(),
 };
 
@@ -0,0 +1,134 @@
+/*
+ * VMware SVGA FIFO Fuzzing Target
+ *
+ * This work is licensed under the terms of the GNU GPL, version 2 or later.
+ * See the COPYING file in the top-level directory.
+ */
+
+#include "qemu/osdep.h"
+
+#include "qemu/bswap.h"
+#include "tests/qtest/libqtest.h"
+#include "tests/qtest/libqos/pci.h"
+#include "tests/qtest/libqos/pci-pc.h"
+#include "fuzz.h"
+
+#define SVGA_INDEX_PORT         0x0
+#define SVGA_VALUE_PORT         0x1
+
+#define SVGA_REG_ENABLE         1
+#define SVGA_REG_WIDTH          2
+#define SVGA_REG_HEIGHT         3
+#define SVGA_REG_MEM_SIZE       19
+#define SVGA_REG_CONFIG_DONE    20
+#define SVGA_REG_SYNC           21
+#define SVGA_REG_BUSY           22
+
+#define SVGA_FIFO_MIN           0
+#define SVGA_FIFO_MAX           1
+#define SVGA_FIFO_NEXT          2
+#define SVGA_FIFO_STOP          3
+#define SVGA_FIFO_NUM_REGS      293
+
+/* Every BUSY read while syncing makes the device run the FIFO again */
+#define SVGA_BUSY_POLLS         8
+
+static QPCIBus *pcibus;
+static QPCIDevice *dev;
+static QPCIBar io_bar;
+static QPCIBar fifo_bar;
+static uint32_t fifo_size;
+
+static void svga_write_reg(uint32_t index, uint32_t value)
+{
+    qpci_io_writel(dev, io_bar, SVGA_INDEX_PORT, index);
+    qpci_io_writel(dev, io_bar, SVGA_VALUE_PORT, value);
+}
+
+static uint32_t svga_read_reg(uint32_t index)
+{
+    qpci_io_writel(dev, io_bar, SVGA_INDEX_PORT, index);
+    return qpci_io_readl(dev, io_bar, SVGA_VALUE_PORT);
+}
+
+static void vmware_svga_pre_fuzz(QTestState *s)
+{
+    pcibus = qpci_new_pc(s, NULL);
+    dev = qpci_device_find(pcibus, QPCI_DEVFN(0x4, 0x0));
+    g_assert(dev);
+    qpci_device_enable(dev);
+    io_bar = qpci_iomap(dev, 0, NULL);
+    fifo_bar = qpci_iomap(dev, 2, NULL);
+    fifo_size = svga_read_reg(SVGA_REG_MEM_SIZE);
+}
+
+/*
+ * The input is a 16-bit word offset at which the command stream starts
+ * in the FIFO, so that wrap-around at FIFO_MAX gets exercised, followed
+ * by raw little-endian command words.
+ */
+static void vmware_svga_fuzz(QTestState *s, const unsigned char *data,
+                             size_t size)
+{
+    uint32_t min = SVGA_FIFO_NUM_REGS * 4;
+    uint32_t capacity = fifo_size - min;
+    uint32_t next, word;
+    uint16_t start;
+    size_t i;
+
+    if (size < sizeof(start)) {
+        return;
+    }
+    memcpy(&start, data, sizeof(start));
+    data += sizeof(start);
+    size -= sizeof(start);
+
+    /* one word of the FIFO always stays unused */
+    size = MIN(size, (size_t)capacity - 4) & ~(size_t)3;
+    next = min + (le16_to_cpu(start) * 4) % capacity;
+
+    svga_write_reg(SVGA_REG_ENABLE, 0);
+    svga_write_reg(SVGA_REG_WIDTH, 640);
+    svga_write_reg(SVGA_REG_HEIGHT, 480);
+    qpci_io_writel(dev, fifo_bar, SVGA_FIFO_MIN * 4, min);
+    qpci_io_writel(dev, fifo_bar, SVGA_FIFO_MAX * 4, fifo_size);
+    qpci_io_writel(dev, fifo_bar, SVGA_FIFO_STOP * 4, next);
+
+    for (i = 0; i < size; i += sizeof(word)) {
+        memcpy(&word, data + i, sizeof(word));
+        qpci_io_writel(dev, fifo_bar, next, le32_to_cpu(word));
+        next += sizeof(word);
+        if (next == fifo_size) {
+            next = min;
+        }
+    }
+    qpci_io_writel(dev, fifo_bar, SVGA_FIFO_NEXT * 4, next);
+
+    svga_write_reg(SVGA_REG_CONFIG_DONE, 1);
+    svga_write_reg(SVGA_REG_ENABLE, 1);
+    svga_write_reg(SVGA_REG_SYNC, 1);
+    for (i = 0; i < SVGA_BUSY_POLLS && svga_read_reg(SVGA_REG_BUSY); i++) {
+        /* nothing, the read itself drives the device */
+    }
+    flush_events(s);
+}
+
+static GString *vmware_svga_argv(FuzzTarget *t)
+{
+    return g_string_new(TARGET_NAME " -machine accel=qtest -m 0 "
+                        "-display none -nodefaults "
+                        "-device vmware-svga,addr=04.0");
+}
+
+static void register_vmware_svga_fuzz_targets(void)
+{
+    fuzz_add_target(&(FuzzTarget){
+                .name = "vmware-svga-fifo-fuzz",
+                .description = "Fuzz the VMware SVGA command FIFO parser "
+                               "by feeding raw command words through SYNC",
+                .get_init_cmdline = vmware_svga_argv,
+                .pre_fuzz = vmware_svga_pre_fuzz,
+                .fuzz = vmware_svga_fuzz,});
+}
+
+fuzz_target_init(register_vmware_svga_fuzz_targets);
@@ -0,0 +1,759 @@
+/*
+ * QTest testcase for the VMware SVGA II device
+ *
+ * This work is licensed under the terms of the GNU GPL, version 2 or later.
+ * See the COPYING file in the top-level directory.
+ */
+
+#include "qemu/osdep.h"
+#include "qemu/bswap.h"
+#include "libqtest.h"
+#include "libqos/pci.h"
+#include "libqos/pci-pc.h"
+
+#define SVGA_INDEX_PORT         0x0
+#define SVGA_VALUE_PORT         0x1
+#define SVGA_IRQSTATUS_PORT     0x8
+
+#define SVGA_ID_2               0x90000002
+
+#define SVGA_REG_ID             0
+#define SVGA_REG_ENABLE         1
+#define SVGA_REG_WIDTH          2
+#define SVGA_REG_HEIGHT         3
+#define SVGA_REG_BITS_PER_PIXEL 7
+#define SVGA_REG_BYTES_PER_LINE 12
+#define SVGA_REG_FB_START       13
+#define SVGA_REG_VRAM_SIZE      15
+#define SVGA_REG_CAPABILITIES   17
+#define SVGA_REG_MEM_SIZE       19
+#define SVGA_REG_CONFIG_DONE    20
+#define SVGA_REG_SYNC           21
+#define SVGA_REG_BUSY           22
+#define SVGA_REG_IRQMASK        33
+#define SVGA_REG_TRACES         45
+
+#define SVGA_REG_ENABLE_ENABLE  (1 << 0)
+#define SVGA_REG_ENABLE_HIDE    (1 << 1)
+
+#define SVGA_FIFO_MIN           0
+#define SVGA_FIFO_MAX           1
+#define SVGA_FIFO_NEXT          2
+#define SVGA_FIFO_STOP          3
+#define SVGA_FIFO_NUM_REGS      293
+
+#define SVGA_CAP_RECT_FILL      (1 << 0)
+#define SVGA_CAP_IRQMASK        (1 << 18)
+#define SVGA_CAP_TRACES         (1 << 21)
+
+#define SVGA_IRQFLAG_FIFO_PROGRESS      0x2
+
+#define SVGA_CMD_UPDATE                 1
+#define SVGA_CMD_RECT_FILL              2
+#define SVGA_CMD_RECT_COPY              3
+#define SVGA_CMD_DEFINE_CURSOR          19
+#define SVGA_CMD_DEFINE_ALPHA_CURSOR    22
+
+/* Guest side view of the device, playing the part of the guest driver */
+typedef struct VMSVGAGuest {
+    QTestState *qts;
+    QPCIBus *pcibus;
+    QPCIDevice *dev;
+    QPCIBar io;
+    QPCIBar fifo;
+    uint32_t fifo_min;
+    uint32_t fifo_max;
+    uint32_t next;
+} VMSVGAGuest;
+
+static void svga_write_reg(VMSVGAGuest *g, uint32_t index, uint32_t value)
+{
+    qpci_io_writel(g->dev, g->io, SVGA_INDEX_PORT, index);
+    qpci_io_writel(g->dev, g->io, SVGA_VALUE_PORT, value);
+}
+
+static uint32_t svga_read_reg(VMSVGAGuest *g, uint32_t index)
+{
+    qpci_io_writel(g->dev, g->io, SVGA_INDEX_PORT, index);
+    return qpci_io_readl(g->dev, g->io, SVGA_VALUE_PORT);
+}
+
+static uint32_t fifo_read_reg(VMSVGAGuest *g, uint32_t reg)
+{
+    return qpci_io_readl(g->dev, g->fifo, reg * 4);
+}
+
+static void fifo_write_reg(VMSVGAGuest *g, uint32_t reg, uint32_t value)
+{
+    qpci_io_writel(g->dev, g->fifo, reg * 4, value);
+}
+
+/*
+ * Program the FIFO layout the way a guest driver does before CONFIG_DONE,
+ * with a command area of @size bytes and NEXT and STOP starting @start
+ * bytes into it.
+ */
+static void fifo_init_size(VMSVGAGuest *g, uint32_t size, uint32_t start)
+{
+    g->fifo_min = SVGA_FIFO_NUM_REGS * 4;
+    g->fifo_max = g->fifo_min + size;
+    g_assert_cmpuint(g->fifo_max, <=, svga_read_reg(g, SVGA_REG_MEM_SIZE));
+    g->next = g->fifo_min + start;
+    g_assert_cmpuint(g->next, <, g->fifo_max);
+
+    fifo_write_reg(g, SVGA_FIFO_MIN, g->fifo_min);
+    fifo_write_reg(g, SVGA_FIFO_MAX, g->fifo_max);
+    fifo_write_reg(g, SVGA_FIFO_NEXT, g->next);
+    fifo_write_reg(g, SVGA_FIFO_STOP, g->next);
+    svga_write_reg(g, SVGA_REG_CONFIG_DONE, 1);
+}
+
+/* Use all of the FIFO memory for commands */
+static void fifo_init(VMSVGAGuest *g, uint32_t start)
+{
+    fifo_init_size(g, svga_read_reg(g, SVGA_REG_MEM_SIZE) -
+                   SVGA_FIFO_NUM_REGS * 4, start);
+}
+
+static uint32_t fifo_free_space(VMSVGAGuest *g)
+{
+    uint32_t size = g->fifo_max - g->fifo_min;
+    uint32_t stop = fifo_read_reg(g, SVGA_FIFO_STOP);
+    uint32_t used = (g->next + size - stop) % size;
+
+    /* one word always stays unused so that a full FIFO is not empty */
+    return size - used - 4;
+}
+
+/* Append one command and make it visible to the device */
+static void fifo_write_cmd(VMSVGAGuest *g, const uint32_t *words, size_t len)
+{
+    g_assert_cmpuint(len * 4, <=, fifo_free_space(g));
+
+    for (size_t i = 0; i < len; i++) {
+        qpci_io_writel(g->dev, g->fifo, g->next, words[i]);
+        g->next += 4;
+        if (g->next == g->fifo_max) {
+            g->next = g->fifo_min;
+        }
+    }
+    fifo_write_reg(g, SVGA_FIFO_NEXT, g->next);
+}
+
+/* Write SYNC and wait for the device to drop BUSY */
+static void svga_sync(VMSVGAGuest *g)
+{
+    int tries = 1000;
+
+    svga_write_reg(g, SVGA_REG_SYNC, 1);
+    while (svga_read_reg(g, SVGA_REG_BUSY) && --tries) {
+        /* keep polling, each read lets the device make progress */
+    }
+    g_assert_cmpint(tries, >, 0);
+}
+
+/* Sync and check that the device consumed everything written so far */
+static void fifo_sync(VMSVGAGuest *g)
+{
+    svga_sync(g);
+    g_assert_cmphex(fifo_read_reg(g, SVGA_FIFO_STOP), ==, g->next);
+}
+
+/* Return the pixel at (@x, @y) of a screendump as 0xRRGGBB */
+static uint32_t screendump_pixel(VMSVGAGuest *g, int x, int y)
+{
+    g_autofree char *path = NULL;
+    g_autofree char *data = NULL;
+    gsize len, pos;
+    int fd, width, height, maxval, header;
+
+    fd = g_file_open_tmp("vmware-svga-test.XXXXXX", &path, NULL);
+    g_assert_cmpint(fd, >=, 0);
+    close(fd);
+
+    qtest_qmp_assert_success(g->qts, "{ 'execute': 'screendump', "
+                             "'arguments': { 'filename': %s } }", path);
+    g_assert(g_file_get_contents(path, &data, &len, NULL));
+    unlink(path);
+
+    g_assert_cmpint(sscanf(data, "P6 %d %d %d%n",
+                           &width, &height, &maxval, &header), ==, 3);
+    g_assert_cmpint(x, <, width);
+    g_assert_cmpint(y, <, height);
+
+    /* a single whitespace character separates the header from the data */
+    pos = header + 1 + (y * width + x) * 3;
+    g_assert_cmpuint(pos + 3, <=, len);
+    return (uint8_t)data[pos] << 16 | (uint8_t)data[pos + 1] << 8 |
+           (uint8_t)data[pos + 2];
+}
+
+/* Start a VM with the device, @props are extra -device properties */
+static void vmsvga_guest_open_props(VMSVGAGuest *g, const char *props)
+{
+    g->qts = qtest_initf("-vga none -device vmware-svga,addr=04.0%s", props);
+    g->pcibus = qpci_new_pc(g->qts, NULL);
+    g->dev = qpci_device_find(g->pcibus, QPCI_DEVFN(0x4, 0x0));
+    g_assert_nonnull(g->dev);
+    qpci_device_enable(g->dev);
+    g->io = qpci_iomap(g->dev, 0, NULL);
+    g->fifo = qpci_iomap(g->dev, 2, NULL);
+}
+
+static void vmsvga_guest_open(VMSVGAGuest *g)
+{
+    vmsvga_guest_open_props(g, "");
+}
+
+static void vmsvga_guest_init(VMSVGAGuest *g, uint32_t fifo_start)
+{
+    vmsvga_guest_open(g);
+
+    svga_write_reg(g, SVGA_REG_WIDTH, 640);
+    svga_write_reg(g, SVGA_REG_HEIGHT, 480);
+    fifo_init(g, fifo_start);
+    svga_write_reg(g, SVGA_REG_ENABLE, 1);
+}
+
+static void vmsvga_guest_cleanup(VMSVGAGuest *g)
+{
+    g_free(g->dev);
+    qpci_free_pc(g->pcibus);
+    qtest_quit(g->qts);
+}
+
+static void test_fifo_update(void)
+{
+    VMSVGAGuest g;
+    const uint32_t full[] = { SVGA_CMD_UPDATE, 0, 0, 640, 480 };
+    const uint32_t part[] = { SVGA_CMD_UPDATE, 8, 8, 32, 32 };
+
+    vmsvga_guest_init(&g, 0);
+
+    fifo_write_cmd(&g, full, ARRAY_SIZE(full));
+    fifo_write_cmd(&g, part, ARRAY_SIZE(part));
+    fifo_sync(&g);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+static void test_fifo_wrap(void)
+{
+    VMSVGAGuest g;
+    const uint32_t update[] = { SVGA_CMD_UPDATE, 0, 0, 16, 16 };
+
+    /* start two words before the end, so the command straddles MAX */
+    vmsvga_guest_init(&g, 0);
+    svga_write_reg(&g, SVGA_REG_ENABLE, 0);
+    fifo_init(&g, g.fifo_max - g.fifo_min - 8);
+    svga_write_reg(&g, SVGA_REG_ENABLE, 1);
+
+    fifo_write_cmd(&g, update, ARRAY_SIZE(update));
+    g_assert_cmphex(g.next, ==, g.fifo_min + 12);
+    fifo_sync(&g);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+static void test_fifo_bogus_length(void)
+{
+    VMSVGAGuest g;
+    /* 0xffff * 0xffff overflows the argument count of the command */
+    const uint32_t cursor[] = {
+        SVGA_CMD_DEFINE_ALPHA_CURSOR, 0, 0, 0, 0xffff, 0xffff,
+    };
+    const uint32_t update[] = { SVGA_CMD_UPDATE, 0, 0, 640, 480 };
+
+    vmsvga_guest_init(&g, 0);
+
+    fifo_write_cmd(&g, cursor, ARRAY_SIZE(cursor));
+    fifo_sync(&g);
+
+    /* the device must still accept commands afterwards */
+    fifo_write_cmd(&g, update, ARRAY_SIZE(update));
+    fifo_sync(&g);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+/*
+ * FIFO layouts the device must refuse to process.  Each one is followed
+ * by a SYNC, which must complete, and a valid layout, which must work.
+ */
+static void test_fifo_bogus_pointers(void)
+{
+    const uint32_t min = SVGA_FIFO_NUM_REGS * 4;
+    const struct {
+        const char *name;
+        uint32_t min, max, next, stop;
+    } layouts[] = {
+        { "NEXT past MAX", min, min + 16384, min + 16384 + 16, min },
+        { "NEXT at MAX", min, min + 16384, min + 16384, min },
+        { "STOP below MIN", min, min + 16384, min + 16, min - 4 },
+        { "misaligned NEXT", min, min + 16384, min + 2, min },
+        { "misaligned MIN", min + 2, min + 16386, min + 18, min + 2 },
+        { "MAX below MIN + 10K", min, min + 8192, min + 16, min },
+        { "MIN inside the registers", 8, 8 + 16384, 8 + 16, 8 },
+        { "MAX past the FIFO memory", min, 0x20000, min + 16, min },
+    };
+    const uint32_t update[] = { SVGA_CMD_UPDATE, 0, 0, 16, 16 };
+    VMSVGAGuest g;
+
+    vmsvga_guest_init(&g, 0);
+
+    for (size_t i = 0; i < ARRAY_SIZE(layouts); i++) {
+        g_test_message("%s", layouts[i].name);
+        fifo_write_reg(&g, SVGA_FIFO_MIN, layouts[i].min);
+        fifo_write_reg(&g, SVGA_FIFO_MAX, layouts[i].max);
+        fifo_write_reg(&g, SVGA_FIFO_NEXT, layouts[i].next);
+        fifo_write_reg(&g, SVGA_FIFO_STOP, layouts[i].stop);
+        svga_write_reg(&g, SVGA_REG_CONFIG_DONE, 1);
+
+        svga_sync(&g);
+        g_assert_cmphex(fifo_read_reg(&g, SVGA_FIFO_STOP), ==,
+                        layouts[i].stop);
+
+        fifo_init(&g, 0);
+        fifo_write_cmd(&g, update, ARRAY_SIZE(update));
+        fifo_sync(&g);
+    }
+
+    vmsvga_guest_cleanup(&g);
+}
+
+/*
+ * Without a programmed mode the console may still show a VGA surface,
+ * whose geometry has nothing to do with VRAM.  Drawing must be refused.
+ */
+static void test_fifo_no_mode(void)
+{
+    VMSVGAGuest g;
+    QPCIBar fb;
+    const uint32_t fill[] = { SVGA_CMD_RECT_FILL, 0xffffff, 0, 0, 16, 16 };
+
+    vmsvga_guest_open(&g);
+    fb = qpci_iomap(g.dev, 1, NULL);
+    fifo_init(&g, 0);
+    svga_write_reg(&g, SVGA_REG_ENABLE, 1);
+    screendump_pixel(&g, 0, 0);
+
+    fifo_write_cmd(&g, fill, ARRAY_SIZE(fill));
+    fifo_sync(&g);
+    g_assert_cmphex(qpci_io_readl(g.dev, fb, 0), ==, 0);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+/*
+ * With 1 MiB of VRAM, 640x480 at 32 bpp does not fit but 512x480 does.
+ * Whatever the guest programs, drawing must stay inside VRAM.
+ */
+static void test_fifo_small_vram(void)
+{
+    VMSVGAGuest g;
+    QPCIBar fb;
+    const uint32_t vram_size = 1024 * 1024;
+    /* spans the end of VRAM with a 640 pixel pitch */
+    const uint32_t fill[] = { SVGA_CMD_RECT_FILL, 0xffffff, 0, 400, 640, 20 };
+    const uint32_t update[] = { SVGA_CMD_UPDATE, 0, 400, 640, 20 };
+    const uint32_t corner[] = { SVGA_CMD_RECT_FILL, 0x00ff00, 511, 479, 1, 1 };
+
+    vmsvga_guest_open_props(&g, ",vgamem_mb=1");
+    fb = qpci_iomap(g.dev, 1, NULL);
+    g_assert_cmpuint(svga_read_reg(&g, SVGA_REG_VRAM_SIZE), ==, vram_size);
+
+    svga_write_reg(&g, SVGA_REG_WIDTH, 640);
+    svga_write_reg(&g, SVGA_REG_HEIGHT, 480);
+    fifo_init(&g, 0);
+    svga_write_reg(&g, SVGA_REG_ENABLE, 1);
+    g_assert_cmpuint(svga_read_reg(&g, SVGA_REG_ENABLE), ==, 0);
+
+    svga_write_reg(&g, SVGA_REG_WIDTH, 512);
+    svga_write_reg(&g, SVGA_REG_ENABLE, 1);
+    g_assert_cmpuint(svga_read_reg(&g, SVGA_REG_ENABLE), ==, 1);
+    screendump_pixel(&g, 0, 0);
+
+    /* the last pixel of the mode can be drawn */
+    fifo_write_cmd(&g, corner, ARRAY_SIZE(corner));
+    fifo_sync(&g);
+    g_assert_cmphex(qpci_io_readl(g.dev, fb, (479 * 512 + 511) * 4), ==,
+                    0x00ff00);
+
+    /* growing the mode beyond VRAM while enabled does not help */
+    svga_write_reg(&g, SVGA_REG_WIDTH, 640);
+    fifo_write_cmd(&g, fill, ARRAY_SIZE(fill));
+    fifo_write_cmd(&g, update, ARRAY_SIZE(update));
+    fifo_sync(&g);
+    g_assert_cmphex(qpci_io_readl(g.dev, fb, vram_size - 8), ==, 0);
+    screendump_pixel(&g, 0, 0);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+/*
+ * A cursor whose image is valid but larger than the smallest legal FIFO
+ * can never be complete, it must be discarded rather than waited for.
+ */
+static void test_fifo_oversized_cursor(void)
+{
+    VMSVGAGuest g;
+    /* 64x64 at 32 bpp is 128 mask words plus 4096 image words */
+    const uint32_t cursor[] = {
+        SVGA_CMD_DEFINE_CURSOR, 0, 0, 0, 64, 64, 1, 32,
+    };
+    const uint32_t update[] = { SVGA_CMD_UPDATE, 0, 0, 16, 16 };
+
+    vmsvga_guest_init(&g, 0);
+    svga_write_reg(&g, SVGA_REG_ENABLE, 0);
+    fifo_init_size(&g, 10 * 1024, 0);
+    svga_write_reg(&g, SVGA_REG_ENABLE, 1);
+
+    fifo_write_cmd(&g, cursor, ARRAY_SIZE(cursor));
+    fifo_sync(&g);
+
+    fifo_write_cmd(&g, update, ARRAY_SIZE(update));
+    fifo_sync(&g);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+/*
+ * Go through the same steps as a guest driver does when it takes over
+ * the display, and check that drawing reaches both VRAM and the console.
+ */
+static void test_driver_bringup(void)
+{
+    VMSVGAGuest g;
+    QPCIBar fb;
+    const uint32_t fill[] = { SVGA_CMD_RECT_FILL, 0xff8040, 16, 16, 32, 32 };
+    const uint32_t update[] = { SVGA_CMD_UPDATE, 16, 16, 32, 32 };
+
+    vmsvga_guest_open(&g);
+
+    svga_write_reg(&g, SVGA_REG_ID, SVGA_ID_2);
+    g_assert_cmphex(svga_read_reg(&g, SVGA_REG_ID), ==, SVGA_ID_2);
+    g_assert(svga_read_reg(&g, SVGA_REG_CAPABILITIES) & SVGA_CAP_RECT_FILL);
+
+    fb = qpci_iomap(g.dev, 1, NULL);
+    g_assert_cmphex(svga_read_reg(&g, SVGA_REG_FB_START), ==, fb.addr);
+
+    svga_write_reg(&g, SVGA_REG_WIDTH, 640);
+    svga_write_reg(&g, SVGA_REG_HEIGHT, 480);
+    svga_write_reg(&g, SVGA_REG_BITS_PER_PIXEL, 32);
+    g_assert_cmpuint(svga_read_reg(&g, SVGA_REG_BYTES_PER_LINE), ==, 640 * 4);
+
+    fifo_init(&g, 0);
+    svga_write_reg(&g, SVGA_REG_ENABLE, 1);
+
+    /* a display refresh makes the device switch to the new mode */
+    g_assert_cmphex(screendump_pixel(&g, 20, 20), ==, 0);
+
+    fifo_write_cmd(&g, fill, ARRAY_SIZE(fill));
+    fifo_write_cmd(&g, update, ARRAY_SIZE(update));
+    fifo_sync(&g);
+
+    g_assert_cmphex(qpci_io_readl(g.dev, fb, (20 * 640 + 20) * 4), ==,
+                    0xff8040);
+    g_assert_cmphex(screendump_pixel(&g, 20, 20), ==, 0xff8040);
+    g_assert_cmphex(screendump_pixel(&g, 8, 8), ==, 0);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+static void test_enable_hide(void)
+{
+    VMSVGAGuest g;
+    const uint32_t fill[] = { SVGA_CMD_RECT_FILL, 0x123456, 0, 0, 64, 64 };
+
+    vmsvga_guest_init(&g, 0);
+    g_assert_cmphex(screendump_pixel(&g, 8, 8), ==, 0);
+    fifo_write_cmd(&g, fill, ARRAY_SIZE(fill));
+    fifo_sync(&g);
+    g_assert_cmphex(screendump_pixel(&g, 8, 8), ==, 0x123456);
+
+    /* hidden output is blank, but the device keeps its state */
+    svga_write_reg(&g, SVGA_REG_ENABLE,
+                   SVGA_REG_ENABLE_ENABLE | SVGA_REG_ENABLE_HIDE);
+    g_assert_cmphex(svga_read_reg(&g, SVGA_REG_ENABLE), ==,
+                    SVGA_REG_ENABLE_ENABLE | SVGA_REG_ENABLE_HIDE);
+    g_assert_cmphex(screendump_pixel(&g, 8, 8), ==, 0);
+
+    svga_write_reg(&g, SVGA_REG_ENABLE, SVGA_REG_ENABLE_ENABLE);
+    g_assert_cmphex(screendump_pixel(&g, 8, 8), ==, 0x123456);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+/* Drawing while hidden uses the mode programmed in the meantime */
+static void test_enable_hide_mode_change(void)
+{
+    VMSVGAGuest g;
+    const uint32_t fill[] = { SVGA_CMD_RECT_FILL, 0x654321, 300, 100, 16, 16 };
+
+    vmsvga_guest_init(&g, 0);
+    g_assert_cmphex(screendump_pixel(&g, 8, 8), ==, 0);
+
+    svga_write_reg(&g, SVGA_REG_ENABLE,
+                   SVGA_REG_ENABLE_ENABLE | SVGA_REG_ENABLE_HIDE);
+    svga_write_reg(&g, SVGA_REG_WIDTH, 320);
+    g_assert_cmphex(screendump_pixel(&g, 8, 8), ==, 0);
+    fifo_write_cmd(&g, fill, ARRAY_SIZE(fill));
+    fifo_sync(&g);
+    g_assert_cmphex(screendump_pixel(&g, 305, 105), ==, 0);
+
+    svga_write_reg(&g, SVGA_REG_ENABLE, SVGA_REG_ENABLE_ENABLE);
+    g_assert_cmphex(screendump_pixel(&g, 305, 105), ==, 0x654321);
+    g_assert_cmphex(screendump_pixel(&g, 295, 105), ==, 0);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+/* Older machine types take any non-zero ENABLE value as enable */
+static void test_enable_hide_compat(void)
+{
+    VMSVGAGuest g;
+
+    vmsvga_guest_open_props(&g, ",hide=off");
+    svga_write_reg(&g, SVGA_REG_WIDTH, 640);
+    svga_write_reg(&g, SVGA_REG_HEIGHT, 480);
+    fifo_init(&g, 0);
+
+    svga_write_reg(&g, SVGA_REG_ENABLE, SVGA_REG_ENABLE_HIDE);
+    g_assert_cmphex(svga_read_reg(&g, SVGA_REG_ENABLE), ==,
+                    SVGA_REG_ENABLE_ENABLE);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+static void test_irq_fifo_progress(void)
+{
+    VMSVGAGuest g;
+    const uint32_t update[] = { SVGA_CMD_UPDATE, 0, 0, 16, 16 };
+
+    vmsvga_guest_init(&g, 0);
+    g_assert(svga_read_reg(&g, SVGA_REG_CAPABILITIES) & SVGA_CAP_IRQMASK);
+
+    /* a SYNC right after CONFIG_DONE, with an empty FIFO, consumes nothing */
+    svga_write_reg(&g, SVGA_REG_IRQMASK, SVGA_IRQFLAG_FIFO_PROGRESS);
+    g_assert_cmphex(svga_read_reg(&g, SVGA_REG_IRQMASK), ==,
+                    SVGA_IRQFLAG_FIFO_PROGRESS);
+    fifo_sync(&g);
+    g_assert_cmphex(qpci_io_readl(g.dev, g.io, SVGA_IRQSTATUS_PORT), ==, 0);
+
+    /* nothing is flagged while the source is masked */
+    svga_write_reg(&g, SVGA_REG_IRQMASK, 0);
+    fifo_write_cmd(&g, update, ARRAY_SIZE(update));
+    fifo_sync(&g);
+    g_assert_cmphex(qpci_io_readl(g.dev, g.io, SVGA_IRQSTATUS_PORT), ==, 0);
+
+    svga_write_reg(&g, SVGA_REG_IRQMASK, SVGA_IRQFLAG_FIFO_PROGRESS);
+    fifo_write_cmd(&g, update, ARRAY_SIZE(update));
+    fifo_sync(&g);
+    g_assert_cmphex(qpci_io_readl(g.dev, g.io, SVGA_IRQSTATUS_PORT), ==,
+                    SVGA_IRQFLAG_FIFO_PROGRESS);
+
+    /* acknowledging the interrupt clears it */
+    qpci_io_writel(g.dev, g.io, SVGA_IRQSTATUS_PORT,
+                   SVGA_IRQFLAG_FIFO_PROGRESS);
+    g_assert_cmphex(qpci_io_readl(g.dev, g.io, SVGA_IRQSTATUS_PORT), ==, 0);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+static void test_traces(void)
+{
+    VMSVGAGuest g;
+
+    vmsvga_guest_init(&g, 0);
+    g_assert(svga_read_reg(&g, SVGA_REG_CAPABILITIES) & SVGA_CAP_TRACES);
+    g_assert_cmpuint(svga_read_reg(&g, SVGA_REG_TRACES), ==, 0);
+    svga_write_reg(&g, SVGA_REG_TRACES, 1);
+    g_assert_cmpuint(svga_read_reg(&g, SVGA_REG_TRACES), ==, 1);
+    svga_write_reg(&g, SVGA_REG_TRACES, 0);
+    g_assert_cmpuint(svga_read_reg(&g, SVGA_REG_TRACES), ==, 0);
+    vmsvga_guest_cleanup(&g);
+
+    /* older machine types turn the feature off */
+    vmsvga_guest_open_props(&g, ",traces=off");
+    g_assert_false(svga_read_reg(&g, SVGA_REG_CAPABILITIES) &
+                   SVGA_CAP_TRACES);
+    svga_write_reg(&g, SVGA_REG_TRACES, 1);
+    g_assert_cmpuint(svga_read_reg(&g, SVGA_REG_TRACES), ==, 0);
+    vmsvga_guest_cleanup(&g);
+}
+
+/*
+ * With traces on, framebuffer writes reach the console without UPDATE.
+ * The console surface aliases VRAM, so a screendump cannot tell whether
+ * the redraw came from the dirty scan; this checks the scan runs and
+ * leaves the output intact.
+ */
+static void test_traces_direct_write(void)
+{
+    VMSVGAGuest g;
+    QPCIBar fb;
+
+    vmsvga_guest_init(&g, 0);
+    fb = qpci_iomap(g.dev, 1, NULL);
+    svga_write_reg(&g, SVGA_REG_TRACES, 1);
+    g_assert_cmphex(screendump_pixel(&g, 10, 10), ==, 0);
+
+    qpci_io_writel(g.dev, fb, (10 * 640 + 10) * 4, 0xabcdef);
+    g_assert_cmphex(screendump_pixel(&g, 10, 10), ==, 0xabcdef);
+    g_assert_cmphex(screendump_pixel(&g, 11, 10), ==, 0);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+/*
+ * The dirty scan must stay inside VRAM even when the console shows a
+ * surface larger than VRAM, here the 640x480 one left from before SVGA
+ * was enabled without a mode, or the blank one while hidden.
+ */
+static void test_traces_small_vram(void)
+{
+    VMSVGAGuest g;
+
+    vmsvga_guest_open_props(&g, ",vgamem_mb=1");
+    fifo_init(&g, 0);
+    svga_write_reg(&g, SVGA_REG_ENABLE, SVGA_REG_ENABLE_ENABLE);
+    svga_write_reg(&g, SVGA_REG_TRACES, 1);
+    screendump_pixel(&g, 0, 0);
+
+    svga_write_reg(&g, SVGA_REG_WIDTH, 512);
+    svga_write_reg(&g, SVGA_REG_HEIGHT, 480);
+    svga_write_reg(&g, SVGA_REG_ENABLE,
+                   SVGA_REG_ENABLE_ENABLE | SVGA_REG_ENABLE_HIDE);
+    screendump_pixel(&g, 0, 0);
+    svga_write_reg(&g, SVGA_REG_ENABLE, SVGA_REG_ENABLE_ENABLE);
+    g_assert_cmphex(screendump_pixel(&g, 0, 0), ==, 0);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+/*
+ * Reference implementation of the 2D commands for the differential test,
+ * working on the top rows of a 640 pixel wide 32 bpp framebuffer.
+ */
+#define REF_WIDTH       640
+/* Random commands stay in this top-left square so that they overlap */
+#define REF_AREA        64
+#define REF_COMMANDS    256
+
+static void ref_fill(uint32_t *fb, uint32_t colour, int x, int y, int w, int h)
+{
+    for (int j = y; j < y + h; j++) {
+        for (int i = x; i < x + w; i++) {
+            fb[j * REF_WIDTH + i] = colour;
+        }
+    }
+}
+
+static void ref_copy(uint32_t *fb, int x0, int y0, int x1, int y1,
+                     int w, int h)
+{
+    g_autofree uint32_t *tmp = g_new(uint32_t, w * h + 1);
+
+    /* source and destination may overlap, go through a copy */
+    for (int j = 0; j < h; j++) {
+        memcpy(tmp + j * w, fb + (y0 + j) * REF_WIDTH + x0, w * 4);
+    }
+    for (int j = 0; j < h; j++) {
+        memcpy(fb + (y1 + j) * REF_WIDTH + x1, tmp + j * w, w * 4);
+    }
+}
+
+static void random_rect(int *x, int *y, int *w, int *h)
+{
+    *x = g_test_rand_int_range(0, REF_AREA);
+    *y = g_test_rand_int_range(0, REF_AREA);
+    *w = g_test_rand_int_range(0, REF_AREA - *x + 1);
+    *h = g_test_rand_int_range(0, REF_AREA - *y + 1);
+}
+
+/*
+ * Run a random sequence of RECT_FILL and RECT_COPY commands through the
+ * device and through the reference implementation, then compare VRAM and
+ * the console with the expected result.  Use --seed to replay a failure.
+ */
+static void test_fifo_differential(void)
+{
+    VMSVGAGuest g;
+    QPCIBar fb;
+    g_autofree uint32_t *ref = g_new0(uint32_t, REF_WIDTH * REF_AREA);
+    g_autofree uint32_t *vram = g_new(uint32_t, REF_WIDTH * REF_AREA);
+    const uint32_t clear[] = {
+        SVGA_CMD_RECT_FILL, 0, 0, 0, REF_WIDTH, REF_AREA,
+    };
+    int x, y, dx, dy, w, h;
+    uint32_t colour;
+
+    vmsvga_guest_init(&g, 0);
+    fb = qpci_iomap(g.dev, 1, NULL);
+    g_assert_cmphex(screendump_pixel(&g, 0, 0), ==, 0);
+
+    fifo_write_cmd(&g, clear, ARRAY_SIZE(clear));
+    for (int n = 0; n < REF_COMMANDS; n++) {
+        random_rect(&x, &y, &w, &h);
+        colour = g_test_rand_int();
+        dx = g_test_rand_int_range(0, REF_AREA - w + 1);
+        dy = g_test_rand_int_range(0, REF_AREA - h + 1);
+
+        if (g_test_rand_bit()) {
+            const uint32_t fill[] = { SVGA_CMD_RECT_FILL, colour, x, y, w, h };
+
+            ref_fill(ref, colour, x, y, w, h);
+            fifo_write_cmd(&g, fill, ARRAY_SIZE(fill));
+        } else {
+            const uint32_t copy[] = { SVGA_CMD_RECT_COPY, x, y, dx, dy, w, h };
+
+            ref_copy(ref, x, y, dx, dy, w, h);
+            fifo_write_cmd(&g, copy, ARRAY_SIZE(copy));
+        }
+    }
+    fifo_sync(&g);
+
+    qpci_memread(g.dev, fb, 0, vram, REF_WIDTH * REF_AREA * 4);
+    for (int i = 0; i < REF_WIDTH * REF_AREA; i++) {
+        if (le32_to_cpu(vram[i]) != ref[i]) {
+            g_test_message("mismatch at %d,%d", i % REF_WIDTH, i / REF_WIDTH);
+        }
+        g_assert_cmphex(le32_to_cpu(vram[i]), ==, ref[i]);
+    }
+
+    /* the console only shows the colour channels */
+    x = g_test_rand_int_range(0, REF_AREA);
+    y = g_test_rand_int_range(0, REF_AREA);
+    g_assert_cmphex(screendump_pixel(&g, x, y), ==,
+                    ref[y * REF_WIDTH + x] & 0xffffff);
+
+    vmsvga_guest_cleanup(&g);
+}
+
+int main(int argc, char **argv)
+{
+    g_test_init(&argc, &argv, NULL);
+
+    qtest_add_func("/vmware-svga/bringup", test_driver_bringup);
+    qtest_add_func("/vmware-svga/enable-hide", test_enable_hide);
+    qtest_add_func("/vmware-svga/enable-hide/mode-change",
+                   test_enable_hide_mode_change);
+    qtest_add_func("/vmware-svga/enable-hide/compat", test_enable_hide_compat);
+    qtest_add_func("/vmware-svga/fifo/update", test_fifo_update);
+    qtest_add_func("/vmware-svga/fifo/wrap", test_fifo_wrap);
+    qtest_add_func("/vmware-svga/fifo/bogus-length", test_fifo_bogus_length);
+    qtest_add_func("/vmware-svga/fifo/bogus-pointers",
+                   test_fifo_bogus_pointers);
+    qtest_add_func("/vmware-svga/fifo/no-mode", test_fifo_no_mode);
+    qtest_add_func("/vmware-svga/fifo/small-vram", test_fifo_small_vram);
+    qtest_add_func("/vmware-svga/fifo/oversized-cursor",
+                   test_fifo_oversized_cursor);
+    qtest_add_func("/vmware-svga/fifo/differential", test_fifo_differential);
+    qtest_add_func("/vmware-svga/irq/fifo-progress", test_irq_fifo_progress);
+    qtest_add_func("/vmware-svga/traces", test_traces);
+    qtest_add_func("/vmware-svga/traces/direct-write",
+                   test_traces_direct_write);
+    qtest_add_func("/vmware-svga/traces/small-vram", test_traces_small_vram);
+
+    return g_test_run();
+}